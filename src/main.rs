@@ -1,13 +1,425 @@
 #![allow(unused_imports)]
 
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::mem::size_of;
 use std::net::Shutdown;
 use std::net::{TcpListener, TcpStream};
-use std::str::{from_utf8, from_utf8_unchecked};
+use std::str::from_utf8;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
 use uuid::Uuid;
 
+/// A counting semaphore used to bound the number of in-flight requests per
+/// connection, built on the standard `Mutex`/`Condvar` primitives.
+struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a permit is free, then claims it.
+    fn acquire(&self) {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+    }
+
+    /// Returns a permit and wakes one waiter.
+    fn release(&self) {
+        let mut permits = self.permits.lock().unwrap();
+        *permits += 1;
+        self.available.notify_one();
+    }
+}
+
+/// Splits a byte stream into items, modeled on `tokio_util::codec::Decoder`.
+///
+/// `decode` is fed every byte that has arrived so far and either yields a fully
+/// parsed item (consuming its bytes from `src`) or returns `Ok(None)` when only
+/// part of a frame is present, leaving the buffered bytes in place for the next
+/// call.
+trait Decoder {
+    type Item;
+    type Error;
+
+    fn decode(&mut self, src: &mut Vec<u8>) -> Result<Option<Self::Item>, Self::Error>;
+}
+
+/// Writes an item into a byte buffer, modeled on `tokio_util::codec::Encoder`.
+trait Encoder<Item> {
+    type Error;
+
+    fn encode(&mut self, item: Item, dst: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// Length-delimited Kafka wire codec: each frame is an `i32` byte count
+/// followed by that many bytes of request/response payload.
+struct KafkaCodec;
+
+impl Decoder for KafkaCodec {
+    type Item = Request;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut Vec<u8>) -> Result<Option<Request>, DecodeError> {
+        // Wait for the full 4-byte length prefix.
+        if src.len() < size_of::<i32>() {
+            return Ok(None);
+        }
+        let message_size = i32::from_be_bytes(src[0..4].try_into().unwrap());
+        if message_size < 0 {
+            src.drain(0..4);
+            return Err(DecodeError::InvalidLength);
+        }
+        let frame_end = 4 + message_size as usize;
+
+        // Wait until the whole frame body has arrived, preserving buffered bytes.
+        if src.len() < frame_end {
+            return Ok(None);
+        }
+
+        // The full frame is buffered; decode it and drain it regardless of the
+        // outcome so a malformed frame doesn't desynchronize the stream.
+        let parsed = {
+            let mut cursor = &src[4..frame_end];
+            RequestHeader::decode(&mut cursor).map(|header| Request {
+                message_size,
+                header,
+                body: cursor.to_vec(),
+            })
+        };
+        src.drain(0..frame_end);
+        parsed.map(Some)
+    }
+}
+
+impl Encoder<Response> for KafkaCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Response, dst: &mut Vec<u8>) -> Result<(), io::Error> {
+        dst.extend_from_slice(&item.to_bytes());
+        Ok(())
+    }
+}
+
+/// Why a framed read stopped yielding requests: either the socket errored or a
+/// frame was malformed. Keeping the two apart lets the connection handler close
+/// cleanly on bad input instead of treating it as a transport failure.
+enum FrameError {
+    Io(io::Error),
+    Decode(DecodeError),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+/// Couples a [`KafkaCodec`] with a [`TcpStream`], turning the socket into a
+/// source of [`Request`]s and a sink for [`Response`]s. Bytes read from the
+/// socket are buffered across calls so a frame split over several reads is
+/// reassembled transparently.
+struct Framed<'a> {
+    stream: &'a TcpStream,
+    codec: KafkaCodec,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+}
+
+impl<'a> Framed<'a> {
+    fn new(stream: &'a TcpStream) -> Self {
+        Framed {
+            stream,
+            codec: KafkaCodec,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Returns the next fully framed request, `Ok(None)` at end of stream, or a
+    /// [`FrameError`] distinguishing a socket failure from a malformed frame.
+    fn next(&mut self) -> Result<Option<Request>, FrameError> {
+        loop {
+            match self.codec.decode(&mut self.read_buf) {
+                Ok(Some(request)) => return Ok(Some(request)),
+                Ok(None) => {}
+                Err(error) => return Err(FrameError::Decode(error)),
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = (&*self.stream).read(&mut chunk)?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Serializes a response and writes it back on the socket.
+    fn send(&mut self, response: Response) -> io::Result<()> {
+        self.codec.encode(response, &mut self.write_buf)?;
+        (&*self.stream).write_all(&self.write_buf)?;
+        self.write_buf.clear();
+        Ok(())
+    }
+}
+
+/// Error raised while decoding a value off the wire. Kept intentionally small
+/// here; the connection-level handling that turns these into Kafka error
+/// responses lives elsewhere.
+// The payload fields (`field`, `key`) exist to make the `Debug` output
+// actionable when a malformed request is logged; nothing reads them directly.
+#[allow(dead_code)]
+#[derive(Debug)]
+enum DecodeError {
+    /// Ran out of bytes before a value was complete.
+    UnexpectedEof,
+    /// A length prefix was negative or otherwise nonsensical.
+    InvalidLength,
+    /// A field declared as a UTF-8 string held non-UTF-8 bytes.
+    InvalidUtf8 { field: &'static str },
+    /// The request targeted an api key the broker does not implement.
+    UnknownApiKey { key: i16 },
+}
+
+/// A value that can be written to a Kafka wire buffer.
+trait Encodable {
+    fn encode(&self, buf: &mut Vec<u8>);
+}
+
+/// A value that can be read from a Kafka wire buffer. Implementations consume
+/// the bytes they read by advancing `buf`, leaving the remainder for the caller.
+trait Decodable: Sized {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+/// Splits the next `n` bytes off `buf`, advancing it, or fails if too few remain.
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], DecodeError> {
+    if buf.len() < n {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+macro_rules! impl_codec_for_int {
+    ($($t:ty),*) => {$(
+        impl Encodable for $t {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+        }
+
+        impl Decodable for $t {
+            fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+                let bytes = take(buf, size_of::<$t>())?;
+                Ok(<$t>::from_be_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+impl_codec_for_int!(i8, i16, i32, i64);
+
+/// Kafka `ARRAY` of `INT32`: an `i32` element count followed by the elements.
+/// Part of the primitive set; kept available even though no response shape in
+/// the broker currently uses a bare INT32 array.
+#[allow(dead_code)]
+impl Encodable for Vec<i32> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as i32).encode(buf);
+        for v in self {
+            v.encode(buf);
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Decodable for Vec<i32> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let count = i32::decode(buf)?;
+        if count < 0 {
+            return Err(DecodeError::InvalidLength);
+        }
+        (0..count).map(|_| i32::decode(buf)).collect()
+    }
+}
+
+/// Kafka `BYTES`: an `i32` length followed by the raw bytes.
+impl Encodable for Vec<u8> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (self.len() as i32).encode(buf);
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Decodable for Vec<u8> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = i32::decode(buf)?;
+        if len < 0 {
+            return Err(DecodeError::InvalidLength);
+        }
+        Ok(take(buf, len as usize)?.to_vec())
+    }
+}
+
+/// Kafka nullable `STRING`: an `i16` length prefix (`-1` means null) followed
+/// by the UTF-8 bytes.
+impl Encodable for Option<String> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(s) => {
+                (s.len() as i16).encode(buf);
+                buf.extend_from_slice(s.as_bytes());
+            }
+            None => (-1i16).encode(buf),
+        }
+    }
+}
+
+impl Decodable for Option<String> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = i16::decode(buf)?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = take(buf, len as usize)?;
+        let s = from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8 { field: "string" })?;
+        Ok(Some(s.to_owned()))
+    }
+}
+
+/// Writes a KIP-482 `UNSIGNED_VARINT`: seven bits per byte, lowest group
+/// first, with the high bit set on every byte except the last (e.g. `300`
+/// encodes to `0xAC 0x02`).
+fn encode_unsigned_varint(mut value: u32, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads an `UNSIGNED_VARINT`, consuming bytes until one has a clear high bit.
+fn decode_unsigned_varint(buf: &mut &[u8]) -> Result<u32, DecodeError> {
+    let mut result: u32 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = take(buf, 1)?[0];
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+        if shift >= 35 {
+            return Err(DecodeError::InvalidLength);
+        }
+    }
+}
+
+/// Kafka `COMPACT_STRING`: `UNSIGNED_VARINT(len + 1)` then the UTF-8 bytes, with
+/// an encoded `0` meaning null. Provided as part of the compact primitive set;
+/// no current path constructs one, since the one flexible body the broker
+/// writes (ApiVersions v3+) holds no strings and request bodies of ApiVersions
+/// are not decoded.
+#[allow(dead_code)]
+struct CompactString(Option<String>);
+
+impl Encodable for CompactString {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match &self.0 {
+            Some(s) => {
+                encode_unsigned_varint(s.len() as u32 + 1, buf);
+                buf.extend_from_slice(s.as_bytes());
+            }
+            None => encode_unsigned_varint(0, buf),
+        }
+    }
+}
+
+impl Decodable for CompactString {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = decode_unsigned_varint(buf)?;
+        if len == 0 {
+            return Ok(CompactString(None));
+        }
+        let bytes = take(buf, (len - 1) as usize)?;
+        let s = from_utf8(bytes).map_err(|_| DecodeError::InvalidUtf8 { field: "string" })?;
+        Ok(CompactString(Some(s.to_owned())))
+    }
+}
+
+/// Kafka `COMPACT_ARRAY`: `UNSIGNED_VARINT(count + 1)` then each element.
+struct CompactArray<T>(Vec<T>);
+
+impl<T: Encodable> Encodable for CompactArray<T> {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_unsigned_varint(self.0.len() as u32 + 1, buf);
+        for element in &self.0 {
+            element.encode(buf);
+        }
+    }
+}
+
+impl<T: Decodable> Decodable for CompactArray<T> {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let encoded = decode_unsigned_varint(buf)?;
+        if encoded == 0 {
+            return Ok(CompactArray(Vec::new())); // null array, treated as empty
+        }
+        let count = encoded - 1;
+        let elements = (0..count).map(|_| T::decode(buf)).collect::<Result<_, _>>()?;
+        Ok(CompactArray(elements))
+    }
+}
+
+/// Kafka tagged-field buffer: an `UNSIGNED_VARINT` count followed by that many
+/// `(tag, size, data)` triples. An empty buffer serializes to the single byte
+/// `0x00`.
+struct TaggedFields(Vec<(u32, Vec<u8>)>);
+
+impl TaggedFields {
+    fn empty() -> Self {
+        TaggedFields(Vec::new())
+    }
+}
+
+impl Encodable for TaggedFields {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        encode_unsigned_varint(self.0.len() as u32, buf);
+        for (tag, data) in &self.0 {
+            encode_unsigned_varint(*tag, buf);
+            encode_unsigned_varint(data.len() as u32, buf);
+            buf.extend_from_slice(data);
+        }
+    }
+}
+
+impl Decodable for TaggedFields {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let count = decode_unsigned_varint(buf)?;
+        let mut fields = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let tag = decode_unsigned_varint(buf)?;
+            let size = decode_unsigned_varint(buf)? as usize;
+            let data = take(buf, size)?.to_vec();
+            fields.push((tag, data));
+        }
+        Ok(TaggedFields(fields))
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(i16)]
 enum ErrorCode {
@@ -18,26 +430,47 @@ enum ErrorCode {
 #[derive(Debug)]
 struct ApiVersions {
     error_code: ErrorCode,
-    num_api_keys: i8,
     api_keys: Vec<ApiKeys>,
     throttle_time_ms: i32,
+    /// Whether to serialize the body in the flexible (v3+) compact form. The
+    /// client negotiates the version, so this is set from the request.
+    flexible: bool,
 }
 
-impl ApiVersions {
-    fn size(&self) -> usize {
-        size_of::<i16>() +          // error_code
-            size_of::<i8>() +           // num_api_keys
-            self.api_keys.iter().map(|api_key|api_key.size()).sum::<usize>() +      // api_keys
-            size_of::<i32>() // throttle_time_ms
+impl Encodable for ErrorCode {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        (*self as i16).encode(buf);
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut result = Vec::new();
-        result.extend_from_slice(&(self.error_code as i16).to_be_bytes());
-        result.extend_from_slice(&self.num_api_keys.to_be_bytes());
-        result.extend(self.api_keys.iter().flat_map(|api_key| api_key.to_bytes()));
-        result.extend_from_slice(&self.throttle_time_ms.to_be_bytes());
-        result
+/// An ApiVersions entry in a flexible (v3+) response: the `ApiKeys` fields
+/// followed by the per-element tag buffer that flexible arrays require.
+struct FlexibleApiKey(ApiKeys);
+
+impl Encodable for FlexibleApiKey {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.0.encode(buf);
+        TaggedFields::empty().encode(buf);
+    }
+}
+
+impl Encodable for ApiVersions {
+    /// Serializes the body in the form the negotiated version requires. v3+ is
+    /// flexible: the keys go out as a `COMPACT_ARRAY` of tag-buffered entries
+    /// and the body ends with a top-level tag buffer. v0–v2 use the plain
+    /// `ARRAY`/no-tag-buffer layout, since a non-flexible client can't parse the
+    /// compact form.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.error_code.encode(buf);
+        if self.flexible {
+            let keys = self.api_keys.iter().map(|k| FlexibleApiKey(*k)).collect();
+            CompactArray(keys).encode(buf);
+            self.throttle_time_ms.encode(buf);
+            TaggedFields::empty().encode(buf);
+        } else {
+            encode_array(&self.api_keys, buf);
+            self.throttle_time_ms.encode(buf);
+        }
     }
 }
 
@@ -51,9 +484,9 @@ struct Response {
 impl Response {
     fn to_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
-        result.extend_from_slice(&self.message_size.to_be_bytes());
-        result.extend_from_slice(&self.header.to_bytes());
-        result.extend_from_slice(&self.body.to_bytes());
+        self.message_size.encode(&mut result);
+        self.header.encode(&mut result);
+        self.body.encode(&mut result);
         result
     }
 }
@@ -63,54 +496,187 @@ struct ResponseHeader {
     correlation_id: i32,
 }
 
-impl ResponseHeader {
-    fn size(&self) -> usize {
-        size_of::<i32>()
+impl Encodable for ResponseHeader {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.correlation_id.encode(buf);
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        self.correlation_id.to_be_bytes().to_vec()
+#[derive(Debug)]
+enum ResponseBody {
+    ApiVersions(ApiVersions),
+    Fetch(FetchResponse),
+}
+
+impl Encodable for ResponseBody {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ResponseBody::ApiVersions(body) => body.encode(buf),
+            ResponseBody::Fetch(body) => body.encode(buf),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct ApiKeys {
+    api_key: i16,
+    min_version: i16,
+    max_version: i16,
+}
+
+impl Encodable for ApiKeys {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.api_key.encode(buf);
+        self.min_version.encode(buf);
+        self.max_version.encode(buf);
+    }
+}
+
+/// Decodes a Kafka `ARRAY`: an `i32` element count followed by the elements.
+fn decode_array<T: Decodable>(buf: &mut &[u8]) -> Result<Vec<T>, DecodeError> {
+    let count = i32::decode(buf)?;
+    if count < 0 {
+        return Err(DecodeError::InvalidLength);
+    }
+    (0..count).map(|_| T::decode(buf)).collect()
+}
+
+/// Encodes a Kafka `ARRAY`: an `i32` element count followed by the elements.
+fn encode_array<T: Encodable>(items: &[T], buf: &mut Vec<u8>) {
+    (items.len() as i32).encode(buf);
+    for item in items {
+        item.encode(buf);
+    }
+}
+
+/// Fetch request (api_key 1): what a client wants to read, per topic/partition.
+// These fields are decoded off the wire for protocol completeness; the broker
+// doesn't yet act on the tuning knobs (`replica_id`/`max_wait_ms`/`min_bytes`),
+// only on the topic/partition set.
+#[allow(dead_code)]
 #[derive(Debug)]
-struct ResponseBody {
-    api_versions: ApiVersions,
-    tag_buffer: i8,
+struct FetchRequest {
+    replica_id: i32,
+    max_wait_ms: i32,
+    min_bytes: i32,
+    topics: Vec<FetchTopic>,
 }
 
-impl ResponseBody {
-    fn size(&self) -> usize {
-        self.api_versions.size() + size_of::<i8>() * 2 // tag_buffer (used twice in serialization)
+#[derive(Debug)]
+struct FetchTopic {
+    topic: Option<String>,
+    partitions: Vec<FetchPartition>,
+}
+
+// `fetch_offset`/`partition_max_bytes` are parsed but not yet honored (no log
+// layer to seek or bound against), only `partition` is echoed into the response.
+#[allow(dead_code)]
+#[derive(Debug)]
+struct FetchPartition {
+    partition: i32,
+    fetch_offset: i64,
+    partition_max_bytes: i32,
+}
+
+impl Decodable for FetchRequest {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(FetchRequest {
+            replica_id: i32::decode(buf)?,
+            max_wait_ms: i32::decode(buf)?,
+            min_bytes: i32::decode(buf)?,
+            topics: decode_array(buf)?,
+        })
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut result = Vec::new();
-        result.extend_from_slice(&self.api_versions.to_bytes());
-        result.extend_from_slice(&self.tag_buffer.to_be_bytes());
-        result.extend_from_slice(&self.tag_buffer.to_be_bytes());
-        result
+impl Decodable for FetchTopic {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(FetchTopic {
+            topic: Option::<String>::decode(buf)?,
+            partitions: decode_array(buf)?,
+        })
+    }
+}
+
+impl Decodable for FetchPartition {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(FetchPartition {
+            partition: i32::decode(buf)?,
+            fetch_offset: i64::decode(buf)?,
+            partition_max_bytes: i32::decode(buf)?,
+        })
     }
 }
 
+/// Fetch response: mirrors the request's topic/partition shape, carrying a
+/// record-set byte blob per partition (empty until the log layer exists).
 #[derive(Debug)]
-struct ApiKeys {
-    api_key: i16,
-    min_version: i16,
-    max_version: i16,
+struct FetchResponse {
+    throttle_time_ms: i32,
+    topics: Vec<FetchResponseTopic>,
+}
+
+#[derive(Debug)]
+struct FetchResponseTopic {
+    topic: Option<String>,
+    partitions: Vec<FetchResponsePartition>,
+}
+
+#[derive(Debug)]
+struct FetchResponsePartition {
+    partition: i32,
+    error_code: ErrorCode,
+    high_watermark: i64,
+    records: Vec<u8>,
 }
 
-impl ApiKeys {
-    fn size(&self) -> usize {
-        size_of::<i16>() * 3 // api_key, min_version, max_version
+impl Encodable for FetchResponse {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.throttle_time_ms.encode(buf);
+        encode_array(&self.topics, buf);
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut result = Vec::new();
-        result.extend_from_slice(&self.api_key.to_be_bytes());
-        result.extend_from_slice(&self.min_version.to_be_bytes());
-        result.extend_from_slice(&self.max_version.to_be_bytes());
-        result
+impl Encodable for FetchResponseTopic {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.topic.encode(buf);
+        encode_array(&self.partitions, buf);
+    }
+}
+
+impl Encodable for FetchResponsePartition {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.partition.encode(buf);
+        self.error_code.encode(buf);
+        self.high_watermark.encode(buf);
+        self.records.encode(buf);
+    }
+}
+
+/// Builds a Fetch response for `request`, echoing back each requested
+/// topic/partition with an empty record set and a zero high watermark.
+fn handle_fetch(request: &FetchRequest) -> FetchResponse {
+    let topics = request
+        .topics
+        .iter()
+        .map(|topic| FetchResponseTopic {
+            topic: topic.topic.clone(),
+            partitions: topic
+                .partitions
+                .iter()
+                .map(|partition| FetchResponsePartition {
+                    partition: partition.partition,
+                    error_code: ErrorCode::NoError,
+                    high_watermark: 0,
+                    records: Vec::new(),
+                })
+                .collect(),
+        })
+        .collect();
+
+    FetchResponse {
+        throttle_time_ms: 0,
+        topics,
     }
 }
 
@@ -118,6 +684,9 @@ impl ApiKeys {
 struct Request {
     message_size: i32,
     header: RequestHeader,
+    /// The request bytes following the header, to be decoded by the per-API
+    /// handler according to `header.request_api_key`.
+    body: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -139,7 +708,7 @@ fn main() {
             Ok(_stream) => {
                 thread::spawn(move || {
                     println!("accepted new connection");
-                    handle_connection(&_stream);
+                    handle_connection(_stream);
                 });
             }
             Err(e) => {
@@ -148,77 +717,145 @@ fn main() {
         }
     }
 }
-fn handle_connection(mut stream: &TcpStream) {
-    loop {
-        let request = parse_request(stream);
+/// Maximum number of requests a single connection may have in flight at once.
+/// Bounds the work a client can queue before the reader blocks on the socket.
+const MAX_IN_FLIGHT: usize = 16;
 
-        let response = encode_response(&request);
-        let kafka_response = response.to_bytes();
-
-        let _ = stream
-            .write_all(&kafka_response)
-            .map_err(|e| eprintln!("error  {}", e));
-    }
+/// Reassembles items tagged with a monotonic arrival sequence back into that
+/// order. Handlers complete out of order, but Kafka requires in-order replies
+/// per connection, so the writer feeds each completed item here and sends
+/// whatever becomes contiguous from the last one emitted.
+struct ReorderBuffer<T> {
+    next_seq: u64,
+    pending: HashMap<u64, T>,
 }
 
-fn parse_message_size(mut stream: &TcpStream) -> i32 {
-    let mut message_size_buf = [0u8; 4];
-
-    let _ = stream
-        .read_exact(&mut message_size_buf)
-        .map_err(|e| eprintln!("error {}", e));
-    let message_size_slice = &message_size_buf[..];
-    let message_size_i32 = i32::from_be_bytes(message_size_slice.try_into().unwrap());
-    message_size_i32
-}
+impl<T> ReorderBuffer<T> {
+    fn new() -> Self {
+        ReorderBuffer {
+            next_seq: 0,
+            pending: HashMap::new(),
+        }
+    }
 
-fn decode_request_message(message_size_i32: i32, mut stream: &TcpStream) -> RequestHeader {
-    let mut message_buf = vec![0u8; message_size_i32 as usize];
-    let _ = stream
-        .read_exact(&mut message_buf)
-        .map_err(|e| eprintln!("error {}", e));
-    let header = decode_request_header(&message_buf);
-    header
+    /// Records `item` at `seq` and returns the items now contiguous from the
+    /// last drained sequence, in order (empty if `seq` arrived early).
+    fn push(&mut self, seq: u64, item: T) -> Vec<T> {
+        self.pending.insert(seq, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next_seq) {
+            ready.push(item);
+            self.next_seq += 1;
+        }
+        ready
+    }
 }
 
-fn decode_request_header(message_buf: &Vec<u8>) -> RequestHeader {
-    //request_api_key
-    let request_api_key_slice = &message_buf[0..2];
-    let request_api_key = i16::from_be_bytes(request_api_key_slice.try_into().unwrap());
+/// Reads requests continuously and dispatches each to its own worker, so a slow
+/// handler doesn't head-of-line-block the connection. Completed responses are
+/// funneled to a dedicated writer thread and sent back on this connection's
+/// socket only. Kafka requires responses to return in request order on a single
+/// connection, so each request is tagged with an arrival sequence and the writer
+/// reorders completed responses back into that sequence before sending.
+fn handle_connection(stream: TcpStream) {
+    let write_stream = match stream.try_clone() {
+        Ok(write_stream) => write_stream,
+        Err(e) => {
+            eprintln!("error {}", e);
+            return;
+        }
+    };
 
-    //request_api_version
-    let request_api_version_slice = &message_buf[2..4];
-    let request_api_version = i16::from_be_bytes(request_api_version_slice.try_into().unwrap());
+    // Completed responses flow through this channel, each tagged with the
+    // arrival sequence of its request, to the single writer that owns the
+    // socket's write side (so replies for this connection never cross sockets).
+    let (response_tx, response_rx) = mpsc::channel::<(u64, Response)>();
+    let writer = thread::spawn(move || {
+        let mut framed = Framed::new(&write_stream);
+        let mut reorder: ReorderBuffer<Response> = ReorderBuffer::new();
+        for (seq, response) in response_rx {
+            // Send whatever is now contiguous, preserving request order
+            // regardless of the order handlers completed in.
+            for response in reorder.push(seq, response) {
+                if let Err(e) = framed.send(response) {
+                    eprintln!("error {}", e);
+                    return;
+                }
+            }
+        }
+    });
 
-    //correlation_id
-    let correlation_id_slice = &message_buf[4..8];
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut framed = Framed::new(&stream);
+    let mut arrival_seq: u64 = 0;
+    loop {
+        match framed.next() {
+            Ok(Some(request)) => {
+                in_flight.acquire(); // backpressure: cap outstanding work
+                let seq = arrival_seq;
+                arrival_seq += 1;
+                let response_tx = response_tx.clone();
+                let in_flight = Arc::clone(&in_flight);
+                thread::spawn(move || {
+                    let response = encode_response(&request);
+                    let _ = response_tx.send((seq, response));
+                    in_flight.release();
+                });
+            }
+            Ok(None) => break, // client closed the connection
+            Err(FrameError::Io(e)) => {
+                eprintln!("error {}", e);
+                break;
+            }
+            Err(FrameError::Decode(e)) => {
+                // Bad framing leaves us unable to correlate a reply; close this
+                // connection cleanly rather than unwinding the thread.
+                eprintln!("malformed request, closing connection: {:?}", e);
+                break;
+            }
+        }
+    }
 
-    let correlation_id = i32::from_be_bytes(correlation_id_slice.try_into().unwrap());
+    // Drop our sender so the writer drains remaining responses and exits.
+    drop(response_tx);
+    let _ = writer.join();
+}
 
-    //client_id
-    let size_of_client_id_string = &message_buf[8..10];
-    let size_of_client_id_i16 = i16::from_be_bytes(size_of_client_id_string.try_into().unwrap());
-    let client_id = if size_of_client_id_i16 > 0 {
-        let client_id_string_slice = &message_buf[10..10 + size_of_client_id_i16 as usize];
-        let client_id = unsafe { from_utf8_unchecked(client_id_string_slice) };
-        Some(client_id.into())
-    } else {
-        None
-    };
-    RequestHeader {
-        request_api_key,
-        request_api_version,
-        correlation_id,
-        client_id,
+/// Whether `(api_key, api_version)` uses the flexible (KIP-482) request header
+/// v2, which carries a trailing `TAG_BUFFER` after `client_id`.
+fn uses_flexible_header(api_key: i16, api_version: i16) -> bool {
+    match api_key {
+        API_KEY_API_VERSIONS => api_version >= 3,
+        // Fetch only becomes flexible at v12; the broker advertises v1–v2, which
+        // use the non-flexible request header (no trailing tag buffer).
+        _ => false,
     }
 }
 
-fn parse_request(stream: &TcpStream) -> Request {
-    let message_size = parse_message_size(stream);
-    let header = decode_request_message(message_size, stream);
-    Request {
-        message_size,
-        header,
+impl Decodable for RequestHeader {
+    fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
+        let request_api_key = i16::decode(buf)?;
+        let request_api_version = i16::decode(buf)?;
+        let correlation_id = i32::decode(buf)?;
+        let client_id = Option::<String>::decode(buf).map_err(|e| match e {
+            DecodeError::InvalidUtf8 { .. } => DecodeError::InvalidUtf8 {
+                field: "client_id",
+            },
+            other => other,
+        })?;
+
+        // Flexible requests (header v2) end with a tagged-field buffer; consume
+        // it here so the per-API body decoder starts on its own first field.
+        if uses_flexible_header(request_api_key, request_api_version) {
+            TaggedFields::decode(buf)?;
+        }
+
+        Ok(RequestHeader {
+            request_api_key,
+            request_api_version,
+            correlation_id,
+            client_id,
+        })
     }
 }
 
@@ -228,40 +865,89 @@ fn encode_response_header(request: &Request) -> ResponseHeader {
     }
 }
 
-fn encode_response_body(request: &Request) -> ResponseBody {
-    let mut api_keys = Vec::new();
-    api_keys.push(ApiKeys {
-        api_key: 18,
-        min_version: 0,
-        max_version: 4,
-    });
-    api_keys.push(ApiKeys {
-        api_key: 1,
-        min_version: 0,
-        max_version: 16,
-    });
+/// Kafka api keys this broker understands.
+const API_KEY_FETCH: i16 = 1;
+const API_KEY_API_VERSIONS: i16 = 18;
+
+/// Routes a request to its per-API handler based on `request_api_key`,
+/// returning the typed response body for that API. Fetch versions outside the
+/// advertised v1–v2 get an ApiVersions body carrying `UnsupportedVersion`.
+/// Unknown keys return `DecodeError::UnknownApiKey`, which `encode_response`
+/// turns into that same well-formed reply.
+fn dispatch(request: &Request) -> Result<ResponseBody, DecodeError> {
+    match request.header.request_api_key {
+        API_KEY_API_VERSIONS => Ok(handle_api_versions(request)),
+        API_KEY_FETCH => match request.header.request_api_version {
+            1..=2 => handle_fetch_request(request),
+            _ => Ok(unsupported_api_versions(request)),
+        },
+        key => Err(DecodeError::UnknownApiKey { key }),
+    }
+}
+
+fn handle_api_versions(request: &Request) -> ResponseBody {
+    let api_keys = vec![
+        ApiKeys {
+            api_key: API_KEY_API_VERSIONS,
+            min_version: 0,
+            max_version: 4,
+        },
+        ApiKeys {
+            api_key: API_KEY_FETCH,
+            // Only advertise the Fetch versions this broker actually parses and
+            // emits: v1–v2 share the request layout decoded by `FetchRequest`
+            // (v3 adds `max_bytes`, v4 `isolation_level`, v7 session fields) and
+            // the v1 response layout with a top-level `throttle_time_ms`. Fetch
+            // stays non-flexible below v12, matching the wire shapes here.
+            min_version: 1,
+            max_version: 2,
+        },
+    ];
 
     let error_code = match request.header.request_api_version {
         0..=4 => ErrorCode::NoError,
         _ => ErrorCode::UnsupportedVersion,
     };
-    let api_versions = ApiVersions {
+    ResponseBody::ApiVersions(ApiVersions {
         error_code,
-        num_api_keys: 2,
         api_keys,
         throttle_time_ms: 0,
-    };
-    let throttle_time_ms = 0;
-    ResponseBody {
-        api_versions,
-        tag_buffer: 0,
-    }
+        flexible: request.header.request_api_version >= 3,
+    })
+}
+
+fn handle_fetch_request(request: &Request) -> Result<ResponseBody, DecodeError> {
+    let mut body = request.body.as_slice();
+    let fetch = FetchRequest::decode(&mut body)?;
+    Ok(ResponseBody::Fetch(handle_fetch(&fetch)))
+}
+
+/// An ApiVersions body flagged `UnsupportedVersion`, used for api keys or
+/// versions the broker doesn't implement. Encoded to match the requested
+/// ApiVersions version so the client can still parse the error.
+fn unsupported_api_versions(request: &Request) -> ResponseBody {
+    ResponseBody::ApiVersions(ApiVersions {
+        error_code: ErrorCode::UnsupportedVersion,
+        api_keys: Vec::new(),
+        throttle_time_ms: 0,
+        flexible: request.header.request_api_version >= 3,
+    })
 }
 
 fn encode_response(request: &Request) -> Response {
     let header = encode_response_header(&request);
-    let body = encode_response_body(&request);
-    let message_size = (header.size() + body.size()) as i32;
+    // A request we can frame but not handle (unknown key or a malformed body)
+    // still gets a well-formed reply flagged `UnsupportedVersion`, so one bad
+    // request never takes down the connection.
+    let body = dispatch(request).unwrap_or_else(|_| unsupported_api_versions(request));
+
+    // Derive the length prefix from the actual encoded form rather than a
+    // hand-maintained tally.
+    let mut sized = Vec::new();
+    header.encode(&mut sized);
+    body.encode(&mut sized);
+    let message_size = sized.len() as i32;
+
     Response {
         message_size,
         header,
@@ -269,3 +955,70 @@ fn encode_response(request: &Request) -> Response {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsigned_varint_matches_kip482_example() {
+        let mut buf = Vec::new();
+        encode_unsigned_varint(300, &mut buf);
+        assert_eq!(buf, vec![0xAC, 0x02]);
+    }
+
+    #[test]
+    fn unsigned_varint_round_trips() {
+        for value in [0u32, 1, 127, 128, 300, 16_384, u32::MAX] {
+            let mut buf = Vec::new();
+            encode_unsigned_varint(value, &mut buf);
+            let mut slice = buf.as_slice();
+            assert_eq!(decode_unsigned_varint(&mut slice).unwrap(), value);
+            assert!(slice.is_empty(), "decoder left trailing bytes for {value}");
+        }
+    }
+
+    #[test]
+    fn compact_array_frames_count_plus_one() {
+        let mut buf = Vec::new();
+        CompactArray(vec![1i32, 2, 3]).encode(&mut buf);
+        assert_eq!(buf[0], 4); // UNSIGNED_VARINT(count + 1)
+        assert_eq!(&buf[1..], &[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+
+        let mut empty = Vec::new();
+        CompactArray::<i32>(Vec::new()).encode(&mut empty);
+        assert_eq!(empty, vec![1]); // empty array is the single byte 0x01
+    }
+
+    #[test]
+    fn codec_preserves_buffer_until_whole_frame_arrives() {
+        // Fetch v1 request header, client_id = null (-1), no trailing tag buffer.
+        let body = [0, 1, 0, 1, 0, 0, 0, 7, 0xFF, 0xFF];
+        let mut frame = (body.len() as i32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&body);
+
+        let mut codec = KafkaCodec;
+
+        // Only part of the frame present: yields None and keeps the bytes.
+        let mut buf = frame[..6].to_vec();
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 6);
+
+        // Rest arrives: the full request decodes and the buffer is drained.
+        buf.extend_from_slice(&frame[6..]);
+        let request = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(request.header.request_api_key, 1);
+        assert_eq!(request.header.correlation_id, 7);
+        assert_eq!(request.header.client_id, None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn reorder_buffer_drains_in_arrival_order() {
+        let mut reorder: ReorderBuffer<&str> = ReorderBuffer::new();
+        assert!(reorder.push(1, "b").is_empty()); // arrives early, held back
+        assert_eq!(reorder.push(0, "a"), vec!["a", "b"]); // 0 unblocks 1
+        assert!(reorder.push(3, "d").is_empty());
+        assert_eq!(reorder.push(2, "c"), vec!["c", "d"]);
+    }
+}
+